@@ -1,14 +1,39 @@
 use std::ops::Neg;
 use std::str::{FromStr, from_utf8, from_utf8_unchecked};
 
-use de::{Error, Result};
+use base64;
+use de::Error;
+
+pub type Result<T> = ::std::result::Result<T, SpannedError>;
 
 const DIGITS: &[u8] = b"0123456789";
+const HEX_DIGITS: &[u8] = b"0123456789abcdefABCDEF";
+const OCT_DIGITS: &[u8] = b"01234567";
+const BIN_DIGITS: &[u8] = b"01";
 const FLOAT_CHARS: &[u8] = b"0123456789.+-eE";
 const IDENT_FIRST: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz_";
 const IDENT_CHAR: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz_0123456789";
 const WHITE_SPACE: &[u8] = b"\n\t\r ";
 
+pub trait Num: FromStr {
+    fn from_str_radix(src: &str, radix: u32) -> ::std::result::Result<Self, ::std::num::ParseIntError>
+        where Self: Sized;
+}
+
+macro_rules! impl_num {
+    ($($t:ty),*) => {
+        $(
+            impl Num for $t {
+                fn from_str_radix(src: &str, radix: u32) -> ::std::result::Result<Self, ::std::num::ParseIntError> {
+                    <$t>::from_str_radix(src, radix)
+                }
+            }
+        )*
+    };
+}
+
+impl_num!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize);
+
 #[derive(Clone, Copy, Debug)]
 pub struct Bytes<'a> {
     bytes: &'a [u8],
@@ -34,7 +59,7 @@ impl<'a> Bytes<'a> {
     }
 
     pub fn advance_single(&mut self) -> Result<()> {
-        if self.peek().ok_or(Error::Eof)? == b'\n' {
+        if self.peek().ok_or_else(|| self.spanned(Error::Eof))? == b'\n' {
             self.line += 1;
             self.column = 1;
         } else {
@@ -52,47 +77,56 @@ impl<'a> Bytes<'a> {
         } else if self.consume("false") {
             Ok(false)
         } else {
-            Err(Error::ExpectedBoolean)
+            self.error(Error::ExpectedBoolean)
         }
     }
 
+    pub fn byte_buf(&mut self) -> Result<Vec<u8>> {
+        let s = match self.string()? {
+            ParsedStr::Allocated(s) => s,
+            ParsedStr::Slice(s) => s.to_string(),
+        };
+
+        if s.bytes().any(|b| WHITE_SPACE.contains(&b)) {
+            return self.error(Error::InvalidBase64);
+        }
+
+        base64::decode_config(&s, base64::STANDARD)
+            .or_else(|_| base64::decode_config(&s, base64::STANDARD_NO_PAD))
+            .map_err(|_| self.spanned(Error::InvalidBase64))
+    }
+
     pub fn bytes(&self) -> &[u8] {
         &self.bytes
     }
 
     pub fn char(&mut self) -> Result<char> {
         if !self.consume("'") {
-            return Err(Error::ExpectedChar);
+            return self.error(Error::ExpectedChar);
         }
 
-        let c = self.eat_byte()?;
-
-        let c = if c == b'\\' {
-            let c = self.eat_byte()?;
-
-            if c != b'\\' && c != b'\'' {
-                return Err(Error::InvalidEscape);
-            }
+        let c = if self.peek() == Some(b'\\') {
+            let _ = self.advance_single();
 
-            c
+            self.parse_escape_char()?
         } else {
-            c
+            self.eat_char()?
         };
 
         if !self.consume("'") {
-            return Err(Error::ExpectedChar);
+            return self.error(Error::ExpectedChar);
         }
 
-        Ok(c as char)
+        Ok(c)
     }
 
-    pub fn comma(&mut self) -> bool {
+    pub fn comma(&mut self) -> Result<bool> {
         if self.consume(",") {
-            self.skip_ws();
+            self.skip_ws()?;
 
-            true
+            Ok(true)
         } else {
-            false
+            Ok(false)
         }
     }
 
@@ -112,25 +146,43 @@ impl<'a> Bytes<'a> {
 
             Ok(peek)
         } else {
-            Err(Error::Eof)
+            self.error(Error::Eof)
         }
     }
 
+    fn eat_char(&mut self) -> Result<char> {
+        let first = self.eat_byte()?;
+
+        let len = match first {
+            0x00 ... 0x7F => 1,
+            0xC0 ... 0xDF => 2,
+            0xE0 ... 0xEF => 3,
+            0xF0 ... 0xF7 => 4,
+            _ => return self.error(Error::ExpectedChar),
+        };
+
+        let mut buf = [first, 0, 0, 0];
+        for slot in buf[1..len].iter_mut() {
+            *slot = self.eat_byte()?;
+        }
+
+        from_utf8(&buf[..len])
+            .map_err(|e| self.spanned(e.into()))?
+            .chars()
+            .next()
+            .ok_or_else(|| self.spanned(Error::ExpectedChar))
+    }
+
     pub fn float<T>(&mut self) -> Result<T>
         where T: FromStr
     {
-        let num_bytes = self.next_bytes_contained_in(FLOAT_CHARS);
-
-        let s = unsafe { from_utf8_unchecked(&self.bytes[0..num_bytes]) };
-        let res = FromStr::from_str(s).map_err(|_| Error::ExpectedFloat);
-
-        let _ = self.advance(num_bytes);
+        let digits = self.eat_digits(FLOAT_CHARS, Error::ExpectedFloat, Error::ExpectedFloat)?;
 
-        res
+        FromStr::from_str(&digits).map_err(|_| self.spanned(Error::ExpectedFloat))
     }
 
     pub fn identifier(&mut self) -> Result<&[u8]> {
-        if IDENT_FIRST.contains(&self.peek().ok_or(Error::Eof)?) {
+        if IDENT_FIRST.contains(&self.peek().ok_or_else(|| self.spanned(Error::Eof))?) {
             let bytes = self.next_bytes_contained_in(IDENT_CHAR);
 
             let ident = &self.bytes[..bytes];
@@ -138,7 +190,7 @@ impl<'a> Bytes<'a> {
 
             Ok(ident)
         } else {
-            Err(Error::ExpectedIdentifier)
+            self.error(Error::ExpectedIdentifier)
         }
     }
 
@@ -149,17 +201,73 @@ impl<'a> Bytes<'a> {
             .fold(0, |acc, _| acc + 1)
     }
 
-    pub fn skip_ws(&mut self) {
-        while self.peek().map(|c| WHITE_SPACE.contains(&c)).unwrap_or(false) {
-            let _ = self.advance_single();
+    pub fn skip_ws(&mut self) -> Result<()> {
+        loop {
+            if self.peek().map(|c| WHITE_SPACE.contains(&c)).unwrap_or(false) {
+                let _ = self.advance_single();
+
+                continue;
+            }
+
+            if self.consume("//") {
+                while self.peek().map(|c| c != b'\n').unwrap_or(false) {
+                    let _ = self.advance_single();
+                }
+
+                continue;
+            }
+
+            if self.consume("/*") {
+                self.skip_block_comment()?;
+
+                continue;
+            }
+
+            return Ok(());
         }
     }
 
+    fn skip_block_comment(&mut self) -> Result<()> {
+        let mut depth = 1_usize;
+
+        while depth > 0 {
+            if self.consume("/*") {
+                depth += 1;
+            } else if self.consume("*/") {
+                depth -= 1;
+            } else if self.peek().is_some() {
+                let _ = self.advance_single();
+            } else {
+                return self.error(Error::UnclosedComment);
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn peek(&self) -> Option<u8> {
         self.bytes.get(0).map(|b| *b)
     }
 
-    pub fn signed_integer<T>(&mut self) -> Result<T> where T: FromStr + Neg<Output=T> {
+    pub fn position(&self) -> Position {
+        Position {
+            line: self.line,
+            col: self.column,
+        }
+    }
+
+    fn spanned(&self, code: Error) -> SpannedError {
+        SpannedError {
+            code,
+            position: self.position(),
+        }
+    }
+
+    fn error<T>(&self, code: Error) -> Result<T> {
+        Err(self.spanned(code))
+    }
+
+    pub fn signed_integer<T>(&mut self) -> Result<T> where T: FromStr + Num + Neg<Output=T> {
         match self.peek() {
             Some(b'+') => {
                 let _ = self.advance_single();
@@ -172,23 +280,27 @@ impl<'a> Bytes<'a> {
                 self.unsigned_integer::<T>().map(Neg::neg)
             }
             Some(_) => self.unsigned_integer(),
-            None => Err(Error::Eof),
+            None => self.error(Error::Eof),
         }
     }
 
     pub fn string(&mut self) -> Result<ParsedStr> {
+        if self.peek() == Some(b'r') {
+            return self.raw_string();
+        }
+
         if !self.consume("\"") {
-            return Err(Error::ExpectedString);
+            return self.error(Error::ExpectedString);
         }
 
         let (i, end_or_escape) = (0..)
             .flat_map(|i| self.bytes.get(i))
             .enumerate()
             .find(|&(_, &b)| b == b'\\' || b == b'"')
-            .ok_or(Error::Eof)?;
+            .ok_or_else(|| self.spanned(Error::Eof))?;
 
         if *end_or_escape == b'"' {
-            let s = from_utf8(&self.bytes[..i])?;
+            let s = from_utf8(&self.bytes[..i]).map_err(|e| self.spanned(e.into()))?;
 
             // Advance by the number of bytes of the string
             // + 1 for the `"`.
@@ -207,7 +319,7 @@ impl<'a> Bytes<'a> {
                     .flat_map(|i| self.bytes.get(i))
                     .enumerate()
                     .find(|&(_, &b)| b == b'\\' || b == b'"')
-                    .ok_or(Error::Eof)?;
+                    .ok_or_else(|| self.spanned(Error::Eof))?;
 
                 i = new_i;
                 s.extend_from_slice(&self.bytes[..i]);
@@ -215,40 +327,114 @@ impl<'a> Bytes<'a> {
                 if *end_or_escape == b'"' {
                     let _ = self.advance(i + 1);
 
-                    break Ok(ParsedStr::Allocated(String::from_utf8(s)?));
+                    break String::from_utf8(s)
+                        .map(ParsedStr::Allocated)
+                        .map_err(|e| self.spanned(e.into()));
                 }
             }
         }
     }
 
-    pub fn unsigned_integer<T>(&mut self) -> Result<T> where T: FromStr {
-        let num_bytes = self.next_bytes_contained_in(DIGITS);
+    fn raw_string(&mut self) -> Result<ParsedStr<'a>> {
+        let _ = self.advance_single(); // consume `r`
+
+        let num_hashes = self.next_bytes_contained_in(b"#");
+        let _ = self.advance(num_hashes);
+
+        if !self.consume("\"") {
+            return self.error(Error::ExpectedString);
+        }
+
+        let mut i = 0;
+
+        loop {
+            match self.bytes.get(i) {
+                Some(b'"')
+                    if self.bytes[i + 1..].iter().take(num_hashes).filter(|b| **b == b'#').count() == num_hashes =>
+                {
+                    let s = from_utf8(&self.bytes[..i]).map_err(|e| self.spanned(e.into()))?;
+
+                    let _ = self.advance(i + 1 + num_hashes);
+
+                    return Ok(ParsedStr::Slice(s));
+                }
+                Some(_) => i += 1,
+                None => return self.error(Error::Eof),
+            }
+        }
+    }
+
+    pub fn unsigned_integer<T>(&mut self) -> Result<T> where T: FromStr + Num {
+        if self.peek() == Some(b'0') {
+            match self.bytes.get(1).map(|b| *b) {
+                Some(b'x') => {
+                    let _ = self.advance(2);
+                    let digits = self.eat_digits(HEX_DIGITS, Error::Eof, Error::ExpectedInteger)?;
+
+                    return Num::from_str_radix(&digits, 16).map_err(|_| self.spanned(Error::ExpectedInteger));
+                }
+                Some(b'o') => {
+                    let _ = self.advance(2);
+                    let digits = self.eat_digits(OCT_DIGITS, Error::Eof, Error::ExpectedInteger)?;
+
+                    return Num::from_str_radix(&digits, 8).map_err(|_| self.spanned(Error::ExpectedInteger));
+                }
+                Some(b'b') => {
+                    let _ = self.advance(2);
+                    let digits = self.eat_digits(BIN_DIGITS, Error::Eof, Error::ExpectedInteger)?;
+
+                    return Num::from_str_radix(&digits, 2).map_err(|_| self.spanned(Error::ExpectedInteger));
+                }
+                _ => {}
+            }
+        }
+
+        let digits = self.eat_digits(DIGITS, Error::Eof, Error::ExpectedInteger)?;
+
+        FromStr::from_str(&digits).map_err(|_| self.spanned(Error::ExpectedInteger))
+    }
+
+    fn eat_digits(&mut self, allowed: &[u8], eof_err: Error, invalid_err: Error) -> Result<String> {
+        let num_bytes = (0..)
+            .flat_map(|i| self.bytes.get(i))
+            .take_while(|b| allowed.contains(b) || **b == b'_')
+            .fold(0, |acc, _| acc + 1);
 
         if num_bytes == 0 {
-            return Err(Error::Eof);
+            return self.error(eof_err);
         }
 
-        let res = FromStr::from_str(unsafe { from_utf8_unchecked(&self.bytes[0..num_bytes]) })
-            .map_err(|_| Error::ExpectedInteger);
+        let raw = unsafe { from_utf8_unchecked(&self.bytes[0..num_bytes]) };
+
+        if raw.starts_with('_') || raw.ends_with('_') {
+            return self.error(invalid_err);
+        }
+
+        let digits: String = raw.chars().filter(|c| *c != '_').collect();
+
+        if digits.is_empty() {
+            return self.error(invalid_err);
+        }
 
         let _ = self.advance(num_bytes);
 
-        res
+        Ok(digits)
     }
 
-    fn decode_hex_escape(&mut self) -> Result<u16> {
+    fn decode_hex_escape(&mut self, digits: u32) -> Result<u32> {
         let mut n = 0;
-        for _ in 0..4 {
+
+        for _ in 0..digits {
             n = match self.eat_byte()? {
-                c @ b'0' ... b'9' => n * 16_u16 + ((c as u16) - (b'0' as u16)),
-                b'a' | b'A' => n * 16_u16 + 10_u16,
-                b'b' | b'B' => n * 16_u16 + 11_u16,
-                b'c' | b'C' => n * 16_u16 + 12_u16,
-                b'd' | b'D' => n * 16_u16 + 13_u16,
-                b'e' | b'E' => n * 16_u16 + 14_u16,
-                b'f' | b'F' => n * 16_u16 + 15_u16,
+                c @ b'0' ... b'9' => n * 16 + ((c as u32) - (b'0' as u32)),
+                b'a' | b'A' => n * 16 + 10,
+                b'b' | b'B' => n * 16 + 11,
+                b'c' | b'C' => n * 16 + 12,
+                b'd' | b'D' => n * 16 + 13,
+                b'e' | b'E' => n * 16 + 14,
+                b'f' | b'F' => n * 16 + 15,
                 _ => {
-                    return Err(Error::InvalidEscape);
+                    return self.error(Error::InvalidEscape);
                 }
             };
         }
@@ -256,66 +442,62 @@ impl<'a> Bytes<'a> {
         Ok(n)
     }
 
-    fn parse_str_escape(&mut self, store: &mut Vec<u8>) -> Result<()> {
-        use std::iter::repeat;
-
+    fn parse_escape_char(&mut self) -> Result<char> {
         match self.eat_byte()? {
-            b'"' => store.push(b'"'),
-            b'\\' => store.push(b'\\'),
-            b'b' => store.push(b'\x08'),
-            b'f' => store.push(b'\x0c'),
-            b'n' => store.push(b'\n'),
-            b'r' => store.push(b'\r'),
-            b't' => store.push(b'\t'),
+            b'\\' => Ok('\\'),
+            b'\'' => Ok('\''),
+            b'"' => Ok('"'),
+            b'0' => Ok('\0'),
+            b'n' => Ok('\n'),
+            b'r' => Ok('\r'),
+            b't' => Ok('\t'),
+            b'x' => {
+                let n = self.decode_hex_escape(2)?;
+
+                if n > 0x7F {
+                    return self.error(Error::InvalidEscape);
+                }
+
+                Ok(n as u8 as char)
+            }
             b'u' => {
-                let c: char = match self.decode_hex_escape()? {
-                    0xDC00 ... 0xDFFF => {
-                        return Err(Error::InvalidEscape);
-                    }
+                if !self.consume("{") {
+                    return self.error(Error::InvalidEscape);
+                }
 
-                    n1 @ 0xD800 ... 0xDBFF => {
-                        if self.eat_byte()? != b'\\' {
-                            return Err(Error::InvalidEscape);
-                        }
+                let mut n: u32 = 0;
+                let mut num_digits = 0;
 
-                        if self.eat_byte()? != b'u' {
-                            return Err(Error::InvalidEscape);
-                        }
+                while self.peek().map(|c| c != b'}').unwrap_or(false) {
+                    if num_digits >= 6 {
+                        return self.error(Error::InvalidEscape);
+                    }
 
-                        let n2 = self.decode_hex_escape()?;
+                    n = n * 16 + self.decode_hex_escape(1)?;
+                    num_digits += 1;
+                }
 
-                        if n2 < 0xDC00 || n2 > 0xDFFF {
-                            return Err(Error::InvalidEscape);
-                        }
+                if num_digits == 0 || !self.consume("}") {
+                    return self.error(Error::InvalidEscape);
+                }
 
-                        let n = (((n1 - 0xD800) as u32) << 10 | (n2 - 0xDC00) as u32) + 0x1_0000;
+                match n {
+                    0xD800 ... 0xDFFF => self.error(Error::InvalidEscape),
+                    n => ::std::char::from_u32(n).ok_or_else(|| self.spanned(Error::InvalidEscape)),
+                }
+            }
+            _ => self.error(Error::InvalidEscape),
+        }
+    }
 
-                        match ::std::char::from_u32(n as u32) {
-                            Some(c) => c,
-                            None => {
-                                return Err(Error::InvalidEscape);
-                            }
-                        }
-                    }
+    fn parse_str_escape(&mut self, store: &mut Vec<u8>) -> Result<()> {
+        use std::iter::repeat;
 
-                    n => {
-                        match ::std::char::from_u32(n as u32) {
-                            Some(c) => c,
-                            None => {
-                                return Err(Error::InvalidEscape);
-                            }
-                        }
-                    }
-                };
+        let c = self.parse_escape_char()?;
 
-                let char_start = store.len();
-                store.extend(repeat(0).take(c.len_utf8()));
-                c.encode_utf8(&mut store[char_start..]);
-            }
-            _ => {
-                return Err(Error::InvalidEscape);
-            }
-        }
+        let char_start = store.len();
+        store.extend(repeat(0).take(c.len_utf8()));
+        c.encode_utf8(&mut store[char_start..]);
 
         Ok(())
     }
@@ -327,8 +509,134 @@ pub struct Position {
     pub line: usize,
 }
 
+#[derive(Debug)]
+pub struct SpannedError {
+    pub code: Error,
+    pub position: Position,
+}
+
 #[derive(Clone, Debug)]
 pub enum ParsedStr<'a> {
     Allocated(String),
     Slice(&'a str),
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Bytes, ParsedStr, Position};
+
+    #[test]
+    fn test_byte_buf_padded() {
+        let mut bytes = Bytes::new(b"\"aGVsbG8=\"");
+
+        assert_eq!(bytes.byte_buf().unwrap(), b"hello".to_vec());
+    }
+
+    #[test]
+    fn test_byte_buf_unpadded() {
+        let mut bytes = Bytes::new(b"\"aGVsbG8\"");
+
+        assert_eq!(bytes.byte_buf().unwrap(), b"hello".to_vec());
+    }
+
+    #[test]
+    fn test_byte_buf_rejects_whitespace() {
+        let mut bytes = Bytes::new(b"\"aGVs bG8=\"");
+
+        assert!(bytes.byte_buf().is_err());
+    }
+
+    #[test]
+    fn test_unsigned_integer_radix_and_underscores() {
+        assert_eq!(Bytes::new(b"0x1_F").unsigned_integer::<u32>().unwrap(), 31);
+        assert_eq!(Bytes::new(b"0o17").unsigned_integer::<u32>().unwrap(), 15);
+        assert_eq!(Bytes::new(b"0b1010").unsigned_integer::<u32>().unwrap(), 10);
+        assert_eq!(Bytes::new(b"1_000_000").unsigned_integer::<u32>().unwrap(), 1_000_000);
+    }
+
+    #[test]
+    fn test_unsigned_integer_rejects_stray_underscores() {
+        assert!(Bytes::new(b"_1").unsigned_integer::<u32>().is_err());
+        assert!(Bytes::new(b"1_").unsigned_integer::<u32>().is_err());
+        assert!(Bytes::new(b"0x_").unsigned_integer::<u32>().is_err());
+    }
+
+    #[test]
+    fn test_raw_string_hash_terminator() {
+        let mut bytes = Bytes::new(br####"r##"a"#b"##"####);
+
+        match bytes.string().unwrap() {
+            ParsedStr::Slice(s) => assert_eq!(s, "a\"#b"),
+            ParsedStr::Allocated(s) => panic!("expected a borrowed slice, got {:?}", s),
+        }
+    }
+
+    #[test]
+    fn test_error_carries_position() {
+        let mut bytes = Bytes::new(b"1\n2\nnot_a_bool");
+        let _ = bytes.unsigned_integer::<u32>();
+        let _ = bytes.advance_single(); // '\n'
+        let _ = bytes.unsigned_integer::<u32>();
+        let _ = bytes.advance_single(); // '\n'
+
+        let err = bytes.bool().unwrap_err();
+
+        assert_eq!(err.position, Position { line: 3, col: 1 });
+    }
+
+    #[test]
+    fn test_skip_ws_nested_block_comment() {
+        let mut bytes = Bytes::new(b"/* /* */ */1");
+
+        bytes.skip_ws().unwrap();
+
+        assert_eq!(bytes.peek(), Some(b'1'));
+    }
+
+    #[test]
+    fn test_skip_ws_line_comment() {
+        let mut bytes = Bytes::new(b"// hi\n1");
+
+        bytes.skip_ws().unwrap();
+
+        assert_eq!(bytes.peek(), Some(b'1'));
+    }
+
+    #[test]
+    fn test_skip_ws_unclosed_block_comment() {
+        let mut bytes = Bytes::new(b"/* unterminated");
+
+        assert!(bytes.skip_ws().is_err());
+    }
+
+    #[test]
+    fn test_char_hex_escape() {
+        assert_eq!(Bytes::new(b"'\\x41'").char().unwrap(), 'A');
+        assert_eq!(Bytes::new(b"'\\x7F'").char().unwrap(), '\x7F');
+    }
+
+    #[test]
+    fn test_char_hex_escape_rejects_above_0x7f() {
+        assert!(Bytes::new(b"'\\x80'").char().is_err());
+    }
+
+    #[test]
+    fn test_char_unicode_escape_emoji() {
+        assert_eq!(Bytes::new(b"'\\u{1F600}'").char().unwrap(), '\u{1F600}');
+    }
+
+    #[test]
+    fn test_char_unicode_escape_rejects_lone_surrogate() {
+        assert!(Bytes::new(b"'\\u{D800}'").char().is_err());
+    }
+
+    #[test]
+    fn test_char_decodes_multibyte_literal() {
+        assert_eq!(Bytes::new("'日'".as_bytes()).char().unwrap(), '日');
+    }
+
+    #[test]
+    fn test_char_rejects_malformed_utf8_continuation() {
+        assert!(Bytes::new(b"'\xE0\x41\x42'").char().is_err());
+    }
+}